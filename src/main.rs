@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 use std::net::{TcpListener, TcpStream};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::fs;
 use std::thread;
 use std::sync::Arc;
 use std::time::Duration;
 
+use regex::Regex;
+
 pub mod threadpool;
 use threadpool::ThreadPool;
 
@@ -14,106 +16,286 @@ enum HttpError {
   BadRequest,
   NotFound,
   InternalServerError,
+  RequestTimeout,
 }
 
-impl HttpError {
-  fn to_string(&self) -> String {
-    match self {
+impl std::fmt::Display for HttpError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let s = match self {
       Self::BadRequest => "400 Bad Request",
       Self::NotFound => "404 Not Found",
       Self::InternalServerError => "500 Internal Server Error",
-    }.to_string()
+      Self::RequestTimeout => "408 Request Timeout",
+    };
+    write!(f, "{s}")
+  }
+}
+
+impl HttpError {
+  fn status_code(&self) -> u16 {
+    match self {
+      Self::BadRequest => 400,
+      Self::NotFound => 404,
+      Self::InternalServerError => 500,
+      Self::RequestTimeout => 408,
+    }
+  }
+}
+
+/// Looks up the standard reason phrase for a status code, e.g. `200` -> `"OK"`.
+fn reason_phrase(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    201 => "Created",
+    204 => "No Content",
+    301 => "Moved Permanently",
+    302 => "Found",
+    400 => "Bad Request",
+    404 => "Not Found",
+    408 => "Request Timeout",
+    500 => "Internal Server Error",
+    _ => "Unknown",
   }
 }
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 enum HttpMethod {
-  GET,
-  POST,
-  PUT,
-  DELETE,
+  Get,
+  Post,
+  Put,
+  Delete,
 }
 
 #[derive(Debug, Clone)]
 struct HttpRequest {
   method: HttpMethod,
   uri: String,
+  /// Header names are lowercased so lookups are case-insensitive.
+  headers: HashMap<String, String>,
+  query: HashMap<String, String>,
+  body: Option<Vec<u8>>,
+  params: HashMap<String, String>,
+}
+
+/// Decodes `%XX` escapes and turns `+` into a space, as used in query strings.
+fn percent_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'%' if i + 2 < bytes.len()
+        && bytes[i + 1].is_ascii_hexdigit()
+        && bytes[i + 2].is_ascii_hexdigit() =>
+      {
+        let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+        out.push(u8::from_str_radix(hex, 16).unwrap());
+        i += 3;
+      },
+      b'+' => {
+        out.push(b' ');
+        i += 1;
+      },
+      b => {
+        out.push(b);
+        i += 1;
+      },
+    }
+  }
+  String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+  query
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| match pair.split_once('=') {
+      Some((k, v)) => (percent_decode(k), percent_decode(v)),
+      None => (percent_decode(pair), String::new()),
+    })
+    .collect()
 }
 
 impl HttpRequest {
-  fn from_header(req: &Vec<String>) -> Option<HttpRequest> {
-    if req.len() == 0 {
+  fn from_header(req: &[String]) -> Option<HttpRequest> {
+    if req.is_empty() {
       return None;
     }
     let mut words = req[0].split_whitespace();
     let m = words.next().unwrap();
     let r = words.next().unwrap().to_string();
-    match m {
-      "GET" => Some(HttpRequest {
-        method: HttpMethod::GET,
-        uri: r,
-      }),
-      "POST" => Some(HttpRequest {
-        method: HttpMethod::POST,
-        uri: r,
-      }),
-      "PUT" => Some(HttpRequest {
-        method: HttpMethod::PUT,
-        uri: r,
-      }),
-      "DELETE" => Some(HttpRequest {
-        method: HttpMethod::DELETE,
-        uri: r,
-      }),
-      _ => None,
+
+    let method = match m {
+      "GET" => HttpMethod::Get,
+      "POST" => HttpMethod::Post,
+      "PUT" => HttpMethod::Put,
+      "DELETE" => HttpMethod::Delete,
+      _ => return None,
+    };
+
+    let (uri, query) = match r.split_once('?') {
+      Some((path, query)) => (path.to_string(), parse_query(query)),
+      None => (r, HashMap::new()),
+    };
+
+    let mut headers = HashMap::new();
+    for line in &req[1..] {
+      if let Some((name, value)) = line.split_once(':') {
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+      }
     }
+
+    Some(HttpRequest {
+      method,
+      uri,
+      headers,
+      query,
+      body: None,
+      params: HashMap::new(),
+    })
+  }
+}
+
+/// Compiles a route pattern like `/users/{id}` into a regex that matches the
+/// whole URI plus the ordered list of param names captured along the way.
+fn compile_route_pattern(pattern: &str) -> (Regex, Vec<String>) {
+  let mut names = Vec::new();
+  let segments: Vec<String> = pattern
+    .split('/')
+    .map(|seg| {
+      if seg.starts_with('{') && seg.ends_with('}') {
+        names.push(seg[1..seg.len() - 1].to_string());
+        "([^/]+)".to_string()
+      } else {
+        regex::escape(seg)
+      }
+    })
+    .collect();
+  let re = Regex::new(&format!("^{}$", segments.join("/"))).unwrap();
+  (re, names)
+}
+
+#[derive(Debug, Clone)]
+struct Response {
+  status: u16,
+  headers: HashMap<String, String>,
+  body: Vec<u8>,
+}
+
+impl Response {
+  fn new(status: u16, body: Vec<u8>) -> Self {
+    Response { status, headers: HashMap::new(), body }
+  }
+
+  fn ok(body: Vec<u8>) -> Self {
+    Self::new(200, body)
+  }
+
+  fn with_header(mut self, key: &str, value: &str) -> Self {
+    self.headers.insert(key.to_string(), value.to_string());
+    self
   }
 }
 
-type HttpResponse = Result<String, HttpError>;
+type HttpResponse = Result<Response, HttpError>;
 type RouteFn = threadpool::JobFn<TcpStream>;
-type Job = (TcpStream, RouteFn);
 
-struct HttpServer {
+/// Cross-cutting logic that runs around a route handler. `before` can
+/// short-circuit the request by returning `Some`; `after` can inspect or
+/// rewrite the response on the way out.
+trait Middleware {
+  fn before(&self, req: &mut HttpRequest) -> Option<HttpResponse> {
+    let _ = req;
+    None
+  }
+
+  fn after(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+    let _ = (req, resp);
+  }
+}
+
+/// Logs each request's method and URI on the way in, and its status on the
+/// way out.
+struct LoggingMiddleware;
+
+impl Middleware for LoggingMiddleware {
+  fn before(&self, req: &mut HttpRequest) -> Option<HttpResponse> {
+    println!("--> {:?} {}", req.method, req.uri);
+    None
+  }
+
+  fn after(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+    let status = match resp {
+      Ok(r) => r.status,
+      Err(e) => e.status_code(),
+    };
+    println!("<-- {:?} {} {}", req.method, req.uri, status);
+  }
+}
+/// A route handler: takes the parsed request and a handle to the shared
+/// application state.
+type RouteHandler<S> = fn(HttpRequest, Arc<S>) -> HttpResponse;
+/// A compiled `{param}` route: method, matcher, ordered capture names, handler.
+type RegexRoute<S> = (HttpMethod, Regex, Vec<String>, RouteHandler<S>);
+
+struct HttpServer<S = ()> {
   addr: String,
-  routes: HashMap<(HttpMethod, String), RouteFn>,
+  routes: HashMap<(HttpMethod, String), RouteHandler<S>>,
+  regex_routes: Vec<RegexRoute<S>>,
   error_handlers: HashMap<HttpError, RouteFn>,
   threadpool: ThreadPool<TcpStream>,
+  client_timeout: Duration,
+  middleware: Vec<Arc<dyn Middleware + Send + Sync>>,
+  state: Arc<S>,
 }
 
-impl HttpServer {
+impl HttpServer<()> {
   fn new(addr: &str) -> Self {
+    Self::with_state(addr, ())
+  }
+}
+
+impl<S> HttpServer<S>
+where S: Send + Sync + 'static
+{
+  fn with_state(addr: &str, state: S) -> Self {
     let mut error_handlers = HashMap::<HttpError, RouteFn>::new();
 
-    error_handlers.insert(
+    for err in [
       HttpError::NotFound,
-      Arc::new(|mut stream| {
-        stream.write_all("404".as_bytes()).unwrap();
-      }),
-    );
-
-    error_handlers.insert(
       HttpError::BadRequest,
-      Arc::new(|mut stream| {
-        stream.write_all("400".as_bytes()).unwrap();
-      }),
-    );
-
-    error_handlers.insert(
       HttpError::InternalServerError,
-      Arc::new(|mut stream| {
-        stream.write_all("500".as_bytes()).unwrap();
-      }),
-    );
+      HttpError::RequestTimeout,
+    ] {
+      error_handlers.insert(
+        err.clone(),
+        Arc::new(move |mut stream: TcpStream| {
+          stream.write_all(&Self::postprocess_response(Err(err.clone()))).unwrap();
+        }),
+      );
+    }
 
     HttpServer {
       addr: addr.to_string(),
       routes: HashMap::new(),
+      regex_routes: Vec::new(),
       threadpool: ThreadPool::<TcpStream>::new(5),
       error_handlers,
+      client_timeout: Duration::from_secs(30),
+      middleware: Vec::new(),
+      state: Arc::new(state),
     }
   }
 
+  fn with_client_timeout(mut self, timeout: Duration) -> Self {
+    self.client_timeout = timeout;
+    self
+  }
+
+  fn use_middleware(&mut self, middleware: Arc<dyn Middleware + Send + Sync>) {
+    self.middleware.push(middleware);
+  }
+
   fn handle_error(&self, stream: TcpStream, err: HttpError) -> HttpResponse {
     let handler = Arc::clone(
       self.error_handlers.get(&err).unwrap()
@@ -122,32 +304,126 @@ impl HttpServer {
     Err(err)
   }
 
-  fn route(&mut self, stream: TcpStream, req: HttpRequest) -> HttpResponse {
+  fn dispatch(
+    f: RouteHandler<S>,
+    req: HttpRequest,
+    middleware: Vec<Arc<dyn Middleware + Send + Sync>>,
+    state: Arc<S>,
+  ) -> RouteFn {
+    Arc::new(move |mut stream: TcpStream| {
+      let mut req = req.clone();
+      let mut short_circuit = None;
+      for mw in &middleware {
+        if let Some(resp) = mw.before(&mut req) {
+          short_circuit = Some(resp);
+          break;
+        }
+      }
+
+      let mut resp = short_circuit.unwrap_or_else(|| f(req.clone(), Arc::clone(&state)));
+      for mw in middleware.iter().rev() {
+        mw.after(&req, &mut resp);
+      }
+
+      stream.write_all(&Self::postprocess_response(resp)).unwrap();
+    })
+  }
+
+  /// Dispatches `req` to a matching route on the thread pool, or to the
+  /// `NotFound` error handler. The actual `Response` is produced and written
+  /// to `stream` asynchronously on a worker thread, so there is nothing
+  /// meaningful to hand back here beyond whether dispatch succeeded.
+  fn route(&mut self, stream: TcpStream, req: HttpRequest) -> Result<(), HttpError> {
     let key = (req.method.clone(), req.uri.clone());
-    match self.routes.get(&key) {
-      Some(f) => {
-        self.threadpool.execute(Arc::clone(f), stream);
-        Ok("NotImplement: thread not joining".to_string())
-      },
-      None => self.handle_error(stream, HttpError::NotFound),
+    if let Some(&f) = self.routes.get(&key) {
+      let job = Self::dispatch(f, req, self.middleware.clone(), Arc::clone(&self.state));
+      self.threadpool.execute(job, stream);
+      return Ok(());
+    }
+
+    for (method, re, names, f) in &self.regex_routes {
+      if *method != req.method {
+        continue;
+      }
+      if let Some(caps) = re.captures(&req.uri) {
+        let mut req = req.clone();
+        for (name, value) in names.iter().zip(caps.iter().skip(1)) {
+          if let Some(value) = value {
+            req.params.insert(name.clone(), value.as_str().to_string());
+          }
+        }
+        let job = Self::dispatch(*f, req, self.middleware.clone(), Arc::clone(&self.state));
+        self.threadpool.execute(job, stream);
+        return Ok(());
+      }
+    }
+
+    self.handle_error(stream, HttpError::NotFound).map(|_| ())
+  }
+
+  fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+  }
+
+  /// Reads request-line and header lines up to (and excluding) the blank line
+  /// that terminates them. Returns `HttpError::RequestTimeout` if the client
+  /// stalls mid-request.
+  fn read_header_lines(reader: &mut BufReader<&TcpStream>) -> Result<Vec<String>, HttpError> {
+    let mut lines = Vec::new();
+    loop {
+      let mut line = String::new();
+      match reader.read_line(&mut line) {
+        Ok(0) => break,
+        Ok(_) => {
+          let line = line.trim_end_matches(['\r', '\n']).to_string();
+          if line.is_empty() {
+            break;
+          }
+          lines.push(line);
+        },
+        Err(e) if Self::is_timeout(&e) => return Err(HttpError::RequestTimeout),
+        Err(e) => panic!("failed to read request: {e}"),
+      }
     }
+    Ok(lines)
   }
 
   fn respond(&mut self, stream: TcpStream) {
-    let reader = BufReader::new(&stream);
-    let req = reader
-      .lines()
-      .map(|res| res.unwrap())
-      .take_while(|line| !line.is_empty())
-      .collect::<Vec<_>>();
-
-    match HttpRequest::from_header(&req) {
-      Some(r) => {
+    stream.set_read_timeout(Some(self.client_timeout)).unwrap();
+    let mut reader = BufReader::new(&stream);
+
+    let header_lines = match Self::read_header_lines(&mut reader) {
+      Ok(lines) => lines,
+      Err(err) => {
+        drop(reader);
+        let _ = self.handle_error(stream, err);
+        return;
+      },
+    };
+
+    match HttpRequest::from_header(&header_lines) {
+      Some(mut r) => {
+        if let Some(len) = r.headers.get("content-length").and_then(|v| v.parse::<usize>().ok()) {
+          let mut body = vec![0u8; len];
+          match reader.read_exact(&mut body) {
+            Ok(()) => r.body = Some(body),
+            Err(e) if Self::is_timeout(&e) => {
+              drop(reader);
+              let _ = self.handle_error(stream, HttpError::RequestTimeout);
+              return;
+            },
+            Err(e) => panic!("failed to read request body: {e}"),
+          }
+        }
+
         println!("Request: {:?}", r);
-        let resp = self.route(stream, r.clone());
-        println!("Response: {:?}", resp);
+        match self.route(stream, r) {
+          Ok(()) => println!("Response: dispatched to worker"),
+          Err(err) => println!("Response: {:?}", err),
+        }
       },
       None => {
+        drop(reader);
         println!("Bad Request");
         let _ = self.handle_error(stream, HttpError::BadRequest);
       },
@@ -161,43 +437,100 @@ impl HttpServer {
     }
   }
 
-  fn postprocess_response(resp: HttpResponse) -> String {
-    let (status, content) = match resp {
-      Ok(s) => ("200 OK".to_string(), s),
-      Err(e) => {
-        (e.to_string(), e.to_string())
-      },
+  fn postprocess_response(resp: HttpResponse) -> Vec<u8> {
+    let response = match resp {
+      Ok(r) => r,
+      Err(e) => Response::new(e.status_code(), e.to_string().into_bytes()),
     };
-    let len = content.len();
-    format!("HTTP/1.1 {status}\r\nContent-length: {len}\r\n\r\n{content}")
+
+    let mut out = format!(
+      "HTTP/1.1 {} {}\r\n",
+      response.status,
+      reason_phrase(response.status),
+    ).into_bytes();
+
+    for (name, value) in &response.headers {
+      out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    }
+    out.extend_from_slice(format!("Content-Length: {}\r\n\r\n", response.body.len()).as_bytes());
+    out.extend_from_slice(&response.body);
+    out
   }
 
-  fn add_route(&mut self, m: HttpMethod, uri: String, f: fn(HttpRequest) -> HttpResponse) {
-    self.routes.insert(
-      (m.clone(), uri.clone()),
-      Arc::new(move |mut stream: TcpStream| {
-        let req = HttpRequest { method: m.clone(), uri: uri.clone() };
-        stream.write_all(Self::postprocess_response(f(req)).as_bytes()).unwrap();
-      }),
-    );
+  fn add_route(&mut self, m: HttpMethod, uri: String, f: RouteHandler<S>) {
+    if uri.contains('{') {
+      let (re, names) = compile_route_pattern(&uri);
+      self.regex_routes.push((m, re, names, f));
+    } else {
+      self.routes.insert((m, uri), f);
+    }
   }
 }
 
 fn ok_html(filename: &str) -> HttpResponse {
-  match fs::read_to_string(filename) {
-    Ok(s) => Ok(s),
+  match fs::read(filename) {
+    Ok(bytes) => Ok(Response::ok(bytes).with_header("Content-Type", "text/html")),
     Err(_) => Err(HttpError::NotFound),
   }
 }
 
+/// Greets `?name=...` from the query string, echoing the caller's
+/// `User-Agent` header and any request body back in the response.
+fn echo(req: HttpRequest, _: Arc<()>) -> HttpResponse {
+  let name = req.query.get("name").cloned().unwrap_or_else(|| "world".to_string());
+  let agent = req.headers.get("user-agent").cloned().unwrap_or_else(|| "unknown".to_string());
+
+  let mut body = format!("Hello, {name}! (user-agent: {agent})").into_bytes();
+  if let Some(b) = &req.body {
+    body.extend_from_slice(b"\nbody: ");
+    body.extend_from_slice(b);
+  }
+
+  Ok(Response::ok(body).with_header("Content-Type", "text/plain"))
+}
+
 fn main() {
   use HttpMethod::*;
-  let mut app = HttpServer::new("127.0.0.1:8080");
+  let mut app = HttpServer::new("127.0.0.1:8080").with_client_timeout(Duration::from_secs(10));
+
+  app.use_middleware(Arc::new(LoggingMiddleware));
 
-  app.add_route(GET, "/".to_string(), |_| ok_html("hello.html"));
-  app.add_route(GET, "/sleep".to_string(), |_| {
+  app.add_route(Get, "/".to_string(), |_, _| ok_html("hello.html"));
+  app.add_route(Get, "/sleep".to_string(), |_, _| {
     thread::sleep(Duration::from_secs(5));
     ok_html("hello.html")
   });
+  app.add_route(Get, "/echo".to_string(), echo);
+  app.add_route(Post, "/echo".to_string(), echo);
   app.serve();
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn echo_reads_query_headers_and_body() {
+    let mut headers = HashMap::new();
+    headers.insert("user-agent".to_string(), "test-agent".to_string());
+
+    let mut query = HashMap::new();
+    query.insert("name".to_string(), "Ferris".to_string());
+
+    let req = HttpRequest {
+      method: HttpMethod::Post,
+      uri: "/echo".to_string(),
+      headers,
+      query,
+      body: Some(b"ping".to_vec()),
+      params: HashMap::new(),
+    };
+
+    let resp = echo(req, Arc::new(())).unwrap();
+    let body = String::from_utf8(resp.body).unwrap();
+
+    assert!(body.contains("Hello, Ferris!"));
+    assert!(body.contains("test-agent"));
+    assert!(body.contains("ping"));
+  }
+}
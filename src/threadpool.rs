@@ -1,34 +1,47 @@
+use std::marker::PhantomData;
 use std::sync::{mpsc::{self, Sender, Receiver}, Arc, Mutex};
 use std::thread::{self, JoinHandle};
 
 pub type JobFn<T> = Arc<dyn Fn(T) + Send + Sync + 'static>;
 pub type Job<T> = (JobFn<T>, T);
 
+enum Message<T> {
+  NewJob(Job<T>),
+  Terminate,
+}
+
 struct Worker<T> {
   id: usize,
-  handle: JoinHandle<()>,
-  rx: Arc<Mutex<Receiver<Job<T>>>>,
+  handle: Option<JoinHandle<()>>,
+  _marker: PhantomData<T>,
 }
 
-impl<T> Worker<T> 
+impl<T> Worker<T>
 where T: Send + 'static
 {
-  fn new(id: usize, rx: Arc<Mutex<Receiver<Job<T>>>>) -> Self {
-    let _rx = Arc::clone(&rx);
+  fn new(id: usize, rx: Arc<Mutex<Receiver<Message<T>>>>) -> Self {
     let handle = thread::spawn(move || {
       loop {
-        let (f, arg) = _rx.lock().unwrap().recv().unwrap();
-        println!("worker {} executing job", id);
-        f(arg);
+        let message = rx.lock().unwrap().recv().unwrap();
+        match message {
+          Message::NewJob((f, arg)) => {
+            println!("worker {} executing job", id);
+            f(arg);
+          },
+          Message::Terminate => {
+            println!("worker {} terminating", id);
+            break;
+          },
+        }
       }
     });
-    Worker { id, handle, rx }
+    Worker { id, handle: Some(handle), _marker: PhantomData }
   }
 }
 
 pub struct ThreadPool<T> {
   workers: Vec<Worker<T>>,
-  tx: Sender<Job<T>>,
+  tx: Sender<Message<T>>,
 }
 
 impl<T> ThreadPool<T>
@@ -39,43 +52,63 @@ where T: Send + 'static
     let rx = Arc::new(Mutex::new(rx));
     let workers: Vec<Worker<T>>= (0..n)
       .map(|i| Worker::new(i, Arc::clone(&rx))).collect();
-    ThreadPool { workers: workers, tx: tx }
+    ThreadPool { workers, tx }
   }
 
   pub fn execute(&self, f: JobFn<T>, v: T) {
-    self.tx.send((f, v)).unwrap();
+    self.tx.send(Message::NewJob((f, v))).unwrap();
   }
 
   pub fn shutdown(&mut self) {
+    for _ in &self.workers {
+      self.tx.send(Message::Terminate).unwrap();
+    }
+
+    for worker in &mut self.workers {
+      println!("shutting down worker {}", worker.id);
+      if let Some(handle) = worker.handle.take() {
+        handle.join().unwrap();
+      }
+    }
+  }
+}
+
+impl<T> Drop for ThreadPool<T> {
+  fn drop(&mut self) {
+    for _ in &self.workers {
+      let _ = self.tx.send(Message::Terminate);
+    }
+
+    for worker in &mut self.workers {
+      if let Some(handle) = worker.handle.take() {
+        let _ = handle.join();
+      }
+    }
   }
 }
 
 #[cfg(test)]
-fn threadpool_test() {
-    // Create a thread pool with 4 workers
-    let pool = ThreadPool::<String>::new(4);
-
-    // Define closures directly
-    let closure1: JobFn<String> = Arc::new(|value: String| {
-        println!("Executing closure 1 with value: {}", value);
-        thread::sleep(Duration::from_secs(1));
-    });
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::time::Duration;
 
-    let closure2: JobFn<String> = Arc::new(|value: String| {
-        println!("Executing closure 2 with value: {}", value);
-        thread::sleep(Duration::from_secs(2));
-    });
+  #[test]
+  fn shutdown_joins_workers_without_panicking() {
+    let mut pool = ThreadPool::<String>::new(4);
+    let completed = Arc::new(AtomicUsize::new(0));
 
-    let closure3: JobFn<String> = Arc::new(|value: String| {
-        println!("Executing closure 3 with value: {}", value);
-        thread::sleep(Duration::from_secs(3));
-    });
+    for _ in 0..4 {
+      let completed = Arc::clone(&completed);
+      let job: JobFn<String> = Arc::new(move |_| {
+        completed.fetch_add(1, Ordering::SeqCst);
+      });
+      pool.execute(job, String::from("value"));
+    }
 
-    // Execute closures in the thread pool with different values
-    pool.execute(Arc::clone(&closure1), String::from("Value 1"));
-    pool.execute(Arc::clone(&closure1), String::from("Value 1"));
-    pool.execute(Arc::clone(&closure2), String::from("Value 2"));
+    thread::sleep(Duration::from_millis(100));
+    pool.shutdown();
 
-    // Wait for the closures to finish
-    thread::sleep(Duration::from_secs(5));
+    assert_eq!(completed.load(Ordering::SeqCst), 4);
+  }
 }